@@ -0,0 +1,219 @@
+use bitcoin::{
+    network::constants::Network as BtcNetwork,
+    util::{
+        address::Address as BtcAddress,
+        bip32::{ChildNumber, ExtendedPubKey},
+    },
+};
+
+use crate::{
+    types::Result,
+    traits::DatabaseInterface,
+    btc::{
+        btc_types::DepositInfo,
+        btc_database_utils::{
+            get_btc_network_from_db,
+            get_btc_deposit_xpub_from_db,
+            put_btc_deposit_xpub_in_db,
+            get_btc_last_derived_index_from_db,
+            put_btc_last_derived_index_in_db,
+        },
+    },
+};
+
+// NOTE: mirrors `derive_known_addresses` from the UTXO-coin wallets - a
+// single xpub plus an index deterministically regenerates a deposit address,
+// so there's no need to enumerate and store every address up front.
+pub const BTC_ADDRESS_DERIVATION_GAP_LIMIT: u32 = 20;
+
+fn get_db_key_for_index_eth_address(index: u32) -> Vec<u8> {
+    format!("btc-deposit-index-eth-address-{}", index).into_bytes()
+}
+
+/// Persists the ETH recipient address a deposit `index` was issued for. The
+/// BTC address itself is never stored - it's always re-derived from `xpub` -
+/// but which real-world user that index belongs to can't be recovered from
+/// the index alone, so that part has to be recorded at issuance time.
+fn put_eth_address_for_index_in_db<D: DatabaseInterface>(
+    db: &D,
+    index: u32,
+    eth_address: &str,
+) -> Result<()> {
+    db.put(get_db_key_for_index_eth_address(index), eth_address.as_bytes().to_vec())
+}
+
+/// Looks up the ETH recipient address a deposit `index` was issued for, if
+/// any - an index past the highest one ever issued has none.
+fn maybe_get_eth_address_for_index_from_db<D: DatabaseInterface>(
+    db: &D,
+    index: u32,
+) -> Option<String> {
+    db.get(get_db_key_for_index_eth_address(index))
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+}
+
+/// Derives the single P2SH deposit address (and its accompanying
+/// `DepositInfo`) at `index` under `xpub`, non-hardened so that knowledge of
+/// `xpub` alone is enough to regenerate the whole set of deposit scripts.
+/// `eth_address` is the deposit's real metadata (who it was issued to) and
+/// isn't itself derivable from `index` - only the BTC address is.
+pub fn derive_deposit_address(
+    xpub: &ExtendedPubKey,
+    index: u32,
+    eth_address: &str,
+    btc_network: &BtcNetwork,
+) -> Result<(BtcAddress, DepositInfo)> {
+    let child_number = ChildNumber::from_normal_idx(index)?;
+    let derived_pub_key = xpub.ckd_pub(&bitcoin::secp256k1::Secp256k1::new(), child_number)?;
+    let btc_address = BtcAddress::p2shwpkh(&derived_pub_key.public_key, *btc_network)?;
+    info!("✔ Derived deposit address {} at index {}", btc_address, index);
+    Ok((btc_address, DepositInfo::new(index as u64, eth_address.to_string())))
+}
+
+/// Issues the next never-before-used deposit address for `eth_address`,
+/// recording which index it was given. The stored last-derived index is the
+/// next index to issue, so it's advanced to one past the index just issued.
+pub fn issue_new_btc_deposit_address<D: DatabaseInterface>(
+    db: &D,
+    eth_address: &str,
+) -> Result<(BtcAddress, DepositInfo)> {
+    let xpub = get_btc_deposit_xpub_from_db(db)?;
+    let btc_network = get_btc_network_from_db(db)?;
+    let index = get_btc_last_derived_index_from_db(db).unwrap_or(0);
+    info!("✔ Issuing new deposit address at index {} for {}...", index, eth_address);
+    put_eth_address_for_index_in_db(db, index, eth_address)?;
+    put_btc_last_derived_index_in_db(db, &(index + 1))?;
+    derive_deposit_address(&xpub, index, eth_address, &btc_network)
+}
+
+/// Rebuilds the `DepositInfoHashMap`-shaped set of every deposit address
+/// issued so far, from index `0` up to (but not including) `range_end`,
+/// skipping indices that were never actually issued.
+pub fn derive_known_addresses<D: DatabaseInterface>(
+    db: &D,
+    xpub: &ExtendedPubKey,
+    range_end: u32,
+    btc_network: &BtcNetwork,
+) -> Result<Vec<(BtcAddress, DepositInfo)>> {
+    info!("✔ Deriving known deposit addresses up to index {}...", range_end);
+    (0..range_end)
+        .filter_map(|index| {
+            maybe_get_eth_address_for_index_from_db(db, index)
+                .map(|eth_address| derive_deposit_address(xpub, index, &eth_address, btc_network))
+        })
+        .collect()
+}
+
+/// Derives every issued deposit address from index `0` up to
+/// `BTC_ADDRESS_DERIVATION_GAP_LIMIT` past the highest-used index stored in
+/// the db, for discovering fresh deposits without requiring every address to
+/// already be known.
+pub fn derive_addresses_past_last_used_index<D: DatabaseInterface>(
+    db: &D,
+) -> Result<Vec<(BtcAddress, DepositInfo)>> {
+    let xpub = get_btc_deposit_xpub_from_db(db)?;
+    let btc_network = get_btc_network_from_db(db)?;
+    let last_used_index = get_btc_last_derived_index_from_db(db).unwrap_or(0);
+    let range_end = last_used_index + BTC_ADDRESS_DERIVATION_GAP_LIMIT;
+    info!(
+        "✔ Scanning derivation gap limit of {} past last used index {}...",
+        BTC_ADDRESS_DERIVATION_GAP_LIMIT,
+        last_used_index,
+    );
+    derive_known_addresses(db, &xpub, range_end, &btc_network)
+}
+
+/// Persists `xpub` as the root from which deposit addresses are derived,
+/// resetting the stored next index to issue back to zero.
+pub fn put_btc_deposit_xpub_and_reset_index_in_db<D: DatabaseInterface>(
+    db: &D,
+    xpub: &ExtendedPubKey,
+) -> Result<()> {
+    info!("✔ Storing BTC deposit xpub and resetting last-used index...");
+    put_btc_deposit_xpub_in_db(db, xpub)?;
+    put_btc_last_derived_index_in_db(db, &0)
+}
+
+/// Marks `index` as used by advancing the stored next index to issue to one
+/// past it, if it isn't already past it, so the next gap-limit scan starts
+/// from the right place and `index` is never issued again.
+pub fn maybe_update_last_derived_index<D: DatabaseInterface>(
+    db: &D,
+    index: u32,
+) -> Result<()> {
+    let last_used_index = get_btc_last_derived_index_from_db(db).unwrap_or(0);
+    if index >= last_used_index {
+        info!("✔ Updating last-used derivation index to {}...", index);
+        put_btc_last_derived_index_in_db(db, &(index + 1))
+    } else {
+        Ok(())
+    }
+}
+
+/// Looks a `BtcAddress` up against every issued index from `0` up to the
+/// gap limit past the highest-used index, so `maybe_extract_p2sh_utxo` can
+/// resolve a matched script's `DepositInfo` without requiring it to already
+/// be in a stored list. Must cover already-used indices too, not just the
+/// forward gap window, or previously-issued deposit addresses never resolve.
+pub fn maybe_find_deposit_info_for_derived_address<D: DatabaseInterface>(
+    db: &D,
+    btc_address: &BtcAddress,
+) -> Result<Option<DepositInfo>> {
+    let xpub = get_btc_deposit_xpub_from_db(db)?;
+    let btc_network = get_btc_network_from_db(db)?;
+    let last_used_index = get_btc_last_derived_index_from_db(db).unwrap_or(0);
+    let range_end = last_used_index + BTC_ADDRESS_DERIVATION_GAP_LIMIT;
+    Ok(derive_known_addresses(db, &xpub, range_end, &btc_network)?
+        .into_iter()
+        .find(|(derived_address, _)| derived_address == btc_address)
+        .map(|(_, deposit_info)| deposit_info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use crate::test_utils::get_test_database;
+
+    fn get_sample_xpub() -> ExtendedPubKey {
+        ExtendedPubKey::from_str(
+            "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8"
+        ).unwrap()
+    }
+
+    #[test]
+    fn should_derive_deposit_address_deterministically() {
+        let xpub = get_sample_xpub();
+        let btc_network = BtcNetwork::Bitcoin;
+        let (address_1, info_1) = derive_deposit_address(&xpub, 0, "0xethaddress", &btc_network).unwrap();
+        let (address_2, info_2) = derive_deposit_address(&xpub, 0, "0xethaddress", &btc_network).unwrap();
+        assert_eq!(address_1, address_2);
+        assert_eq!(info_1, info_2);
+    }
+
+    #[test]
+    fn should_derive_distinct_addresses_for_distinct_indices() {
+        let xpub = get_sample_xpub();
+        let btc_network = BtcNetwork::Bitcoin;
+        let (address_1, _) = derive_deposit_address(&xpub, 0, "0xethaddress", &btc_network).unwrap();
+        let (address_2, _) = derive_deposit_address(&xpub, 1, "0xethaddress", &btc_network).unwrap();
+        assert_ne!(address_1, address_2);
+    }
+
+    #[test]
+    fn should_find_deposit_info_for_already_used_index_below_last_used() {
+        let db = get_test_database();
+        let xpub = get_sample_xpub();
+        put_btc_deposit_xpub_and_reset_index_in_db(&db, &xpub).unwrap();
+        crate::btc::btc_database_utils::put_btc_network_in_db(&db, &BtcNetwork::Bitcoin).unwrap();
+        let (address, _) = issue_new_btc_deposit_address(&db, "0xethaddress").unwrap();
+        // NOTE: advance `last_used_index` well past the issued index, so a
+        // naive forward-only gap-limit scan would miss it entirely.
+        for _ in 0..5 {
+            issue_new_btc_deposit_address(&db, "0xotheraddress").unwrap();
+        }
+        let result = maybe_find_deposit_info_for_derived_address(&db, &address).unwrap();
+        assert!(result.is_some());
+    }
+}
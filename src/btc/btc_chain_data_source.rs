@@ -0,0 +1,97 @@
+use bitcoin::blockdata::{
+    script::Script as BtcScript,
+    transaction::Transaction as BtcTransaction,
+};
+
+use crate::{
+    types::Result,
+    btc::btc_types::BtcTransactions,
+};
+
+/// A reference to a transaction touching one of the core's known deposit
+/// scripts, as returned by a script-hash history query, without requiring
+/// the whole block it lives in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TxRef {
+    pub txid: bitcoin::Txid,
+    pub block_height: u64,
+}
+
+/// Abstracts over where transaction data touching a set of deposit scripts
+/// comes from. Modeled on Electrum's batched script-hash history queries, so
+/// an integrator can back deposit discovery with an indexer instead of
+/// having to feed every block through the core.
+pub trait BtcChainDataSource {
+    /// Returns, for each of `script_pubkeys`, the transactions that have
+    /// touched it.
+    fn get_script_history_batch(
+        &self,
+        script_pubkeys: &[BtcScript],
+    ) -> Result<Vec<TxRef>>;
+
+    /// Fetches the full transaction for a previously-seen `TxRef`.
+    fn get_tx(&self, tx_ref: &TxRef) -> Result<BtcTransaction>;
+}
+
+/// Preserves today's behaviour: "the history" of a script is just whatever
+/// transactions are found while scanning a wholesale-supplied block.
+pub struct BtcBlockScanningDataSource {
+    transactions: BtcTransactions,
+    block_height: u64,
+}
+
+impl BtcBlockScanningDataSource {
+    pub fn new(transactions: BtcTransactions, block_height: u64) -> Self {
+        BtcBlockScanningDataSource { transactions, block_height }
+    }
+}
+
+impl BtcChainDataSource for BtcBlockScanningDataSource {
+    fn get_script_history_batch(
+        &self,
+        script_pubkeys: &[BtcScript],
+    ) -> Result<Vec<TxRef>> {
+        Ok(self
+            .transactions
+            .iter()
+            .filter(|tx| {
+                tx.output
+                    .iter()
+                    .any(|output| script_pubkeys.contains(&output.script_pubkey))
+            })
+            .map(|tx| TxRef { txid: tx.txid(), block_height: self.block_height })
+            .collect())
+    }
+
+    fn get_tx(&self, tx_ref: &TxRef) -> Result<BtcTransaction> {
+        self.transactions
+            .iter()
+            .find(|tx| tx.txid() == tx_ref.txid)
+            .cloned()
+            .ok_or_else(|| format!("✘ No tx found for txid {}!", tx_ref.txid).into())
+    }
+}
+
+// NOTE: `extract_utxos_from_p2sh_txs::extract_p2sh_utxos_from_txs` is the
+// core extraction path and consumes this trait directly - see that module
+// for the `BtcChainDataSource`-driven implementation.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_get_empty_history_when_no_scripts_match() {
+        let source = BtcBlockScanningDataSource::new(vec![], 1);
+        let result = source.get_script_history_batch(&[]).unwrap();
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn should_error_getting_tx_for_unknown_tx_ref() {
+        let source = BtcBlockScanningDataSource::new(vec![], 1);
+        let tx_ref = TxRef { txid: Default::default(), block_height: 1 };
+        let result = source.get_tx(&tx_ref);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+
+use bitcoin::blockdata::{
+    script::Script as BtcScript,
+    transaction::Transaction as BtcTransaction,
+};
+
+use crate::{
+    types::Result,
+    traits::DatabaseInterface,
+    btc::{
+        btc_chain_data_source::BtcBlockScanningDataSource,
+        btc_types::{BtcUtxoAndValue, BtcUtxosAndValues, DepositInfoHashMap},
+        btc_utxo_set::BtcUtxoSet,
+        extract_utxos_from_p2sh_txs::extract_p2sh_utxos_from_txs,
+        btc_database_utils::{
+            get_btc_network_from_db,
+            get_btc_safety_margin_from_db,
+            put_btc_safety_margin_in_db,
+        },
+    },
+};
+use bitcoin::network::constants::Network as BtcNetwork;
+
+pub const DEFAULT_BTC_SAFETY_MARGIN: u64 = 6;
+
+/// A deposit seen either in the mempool or in one of the last `SAFETY_MARGIN`
+/// blocks, not yet promoted into the durable UTXO set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingDeposit {
+    pub utxo_and_value: BtcUtxoAndValue,
+    pub confirmations: u64,
+    /// The height of the block the deposit was found in, or `None` if it's
+    /// still only seen in the mempool.
+    pub maybe_block_height: Option<u64>,
+}
+
+/// Keyed by `script_pubkey` so a deposit can be looked back up regardless of
+/// which unconfirmed tx or which of the last few blocks it was first seen in.
+/// Holds one deposit per script: where several are seen paying to the same
+/// script, the one with the most confirmations is kept (the first seen, on
+/// a tie).
+pub type PendingDepositCache = HashMap<BtcScript, PendingDeposit>;
+
+fn update_pending_deposit_cache_from_txs(
+    cache: &mut PendingDepositCache,
+    txs: &[BtcTransaction],
+    confirmations: u64,
+    maybe_block_height: Option<u64>,
+    btc_network: &BtcNetwork,
+    deposit_info_hash_map: &DepositInfoHashMap,
+) -> Result<()> {
+    let source = BtcBlockScanningDataSource::new(txs.to_vec(), maybe_block_height.unwrap_or(0));
+    extract_p2sh_utxos_from_txs(&source, deposit_info_hash_map, btc_network)?
+        .iter()
+        .try_for_each(|utxo_and_value| {
+            let outpoint = utxo_and_value.get_outpoint()?;
+            let script_pubkey = match txs
+                .iter()
+                .find(|tx| tx.txid() == outpoint.txid)
+                .and_then(|tx| tx.output.get(outpoint.vout as usize))
+            {
+                Some(tx_output) => tx_output.script_pubkey.clone(),
+                None => return Err(format!(
+                    "✘ Could not find originating output for UTXO at outpoint {}!",
+                    outpoint,
+                ).into()),
+            };
+            cache
+                .entry(script_pubkey)
+                .and_modify(|deposit| {
+                    if confirmations > deposit.confirmations {
+                        *deposit = PendingDeposit {
+                            utxo_and_value: utxo_and_value.clone(),
+                            confirmations,
+                            maybe_block_height,
+                        };
+                    }
+                })
+                .or_insert_with(|| PendingDeposit {
+                    utxo_and_value: utxo_and_value.clone(),
+                    confirmations,
+                    maybe_block_height,
+                });
+            Ok(())
+        })
+}
+
+/// Scans a mempool snapshot plus the last `recent_blocks.len()` confirmed
+/// blocks (ordered tip-first, where `recent_blocks[0]` is at `tip_height`)
+/// for deposits, bumping the confirmation count of each as it's seen further
+/// back from the tip. Nothing here is promoted into the real UTXO set yet -
+/// that only happens once a deposit's confirmation count reaches
+/// `SAFETY_MARGIN`, via `maybe_scan_mempool_and_put_confirmed_deposits_in_db`.
+pub fn scan_mempool_and_recent_blocks_for_deposits(
+    mempool_txs: &[BtcTransaction],
+    recent_blocks: &[Vec<BtcTransaction>],
+    tip_height: u64,
+    btc_network: &BtcNetwork,
+    deposit_info_hash_map: &DepositInfoHashMap,
+) -> Result<PendingDepositCache> {
+    info!("✔ Scanning mempool and {} recent block(s) for deposits...", recent_blocks.len());
+    let mut cache = PendingDepositCache::new();
+    update_pending_deposit_cache_from_txs(
+        &mut cache,
+        mempool_txs,
+        0,
+        None,
+        btc_network,
+        deposit_info_hash_map,
+    )?;
+    recent_blocks
+        .iter()
+        .enumerate()
+        .try_for_each(|(i, block_txs)| {
+            let block_height = tip_height.checked_sub(i as u64).ok_or_else(|| format!(
+                "✘ {} recent block(s) supplied but tip is only at height {}!",
+                recent_blocks.len(),
+                tip_height,
+            ))?;
+            update_pending_deposit_cache_from_txs(
+                &mut cache,
+                block_txs,
+                (i + 1) as u64,
+                Some(block_height),
+                btc_network,
+                deposit_info_hash_map,
+            )
+        })?;
+    Ok(cache)
+}
+
+/// Splits a `PendingDepositCache` into deposits that have reached
+/// `safety_margin` confirmations (ready to promote into the real UTXO set)
+/// and those that haven't yet.
+pub fn partition_deposits_by_safety_margin(
+    cache: &PendingDepositCache,
+    safety_margin: u64,
+) -> (Vec<PendingDeposit>, Vec<PendingDeposit>) {
+    cache
+        .values()
+        .cloned()
+        .partition(|deposit| deposit.confirmations >= safety_margin)
+}
+
+pub fn get_safety_margin_or_default<D: DatabaseInterface>(db: &D) -> u64 {
+    get_btc_safety_margin_from_db(db).unwrap_or(DEFAULT_BTC_SAFETY_MARGIN)
+}
+
+/// Scans the mempool and recent blocks for deposits and promotes every one
+/// that has reached the configured `SAFETY_MARGIN` into the durable,
+/// spend-aware `BtcUtxoSet`, recorded at the height it was actually
+/// confirmed in. A deposit already tracked by the `BtcUtxoSet` (e.g. one
+/// promoted on a previous scan) is skipped, so a deposit that's since been
+/// spent doesn't get silently un-spent by being re-added.
+pub fn maybe_scan_mempool_and_put_confirmed_deposits_in_db<D: DatabaseInterface>(
+    db: &D,
+    mempool_txs: &[BtcTransaction],
+    recent_blocks: &[Vec<BtcTransaction>],
+    tip_height: u64,
+    deposit_info_hash_map: &DepositInfoHashMap,
+) -> Result<Vec<PendingDeposit>> {
+    let btc_network = get_btc_network_from_db(db)?;
+    let safety_margin = get_safety_margin_or_default(db);
+    let cache = scan_mempool_and_recent_blocks_for_deposits(
+        mempool_txs,
+        recent_blocks,
+        tip_height,
+        &btc_network,
+        deposit_info_hash_map,
+    )?;
+    let (confirmed, pending) = partition_deposits_by_safety_margin(&cache, safety_margin);
+    info!(
+        "✔ {} deposit(s) confirmed past safety margin of {}, {} still pending",
+        confirmed.len(),
+        safety_margin,
+        pending.len(),
+    );
+    let utxo_set = BtcUtxoSet::new(db);
+    confirmed
+        .iter()
+        .try_for_each(|deposit| {
+            let outpoint = deposit.utxo_and_value.get_outpoint()?;
+            if utxo_set.contains(&outpoint) {
+                debug!("✔ UTXO at {} already tracked ∴ not re-promoting", outpoint);
+                return Ok(());
+            }
+            utxo_set.add_utxos(
+                &BtcUtxosAndValues::new(vec![deposit.utxo_and_value.clone()]),
+                deposit.maybe_block_height.unwrap_or(tip_height),
+            )
+        })?;
+    Ok(confirmed)
+}
+
+pub fn set_btc_safety_margin<D: DatabaseInterface>(db: &D, safety_margin: u64) -> Result<()> {
+    info!("✔ Setting BTC safety margin to {}...", safety_margin);
+    put_btc_safety_margin_in_db(db, &safety_margin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_sample_pending_deposit(confirmations: u64) -> PendingDeposit {
+        PendingDeposit {
+            utxo_and_value: BtcUtxoAndValue::default(),
+            confirmations,
+            maybe_block_height: None,
+        }
+    }
+
+    #[test]
+    fn should_partition_deposits_by_safety_margin() {
+        let mut cache = PendingDepositCache::new();
+        cache.insert(BtcScript::new(), get_sample_pending_deposit(6));
+        let (confirmed, pending) = partition_deposits_by_safety_margin(&cache, 6);
+        assert_eq!(confirmed.len(), 1);
+        assert_eq!(pending.len(), 0);
+    }
+
+    #[test]
+    fn should_not_confirm_deposit_below_safety_margin() {
+        let mut cache = PendingDepositCache::new();
+        cache.insert(BtcScript::new(), get_sample_pending_deposit(2));
+        let (confirmed, pending) = partition_deposits_by_safety_margin(&cache, 6);
+        assert_eq!(confirmed.len(), 0);
+        assert_eq!(pending.len(), 1);
+    }
+
+    #[test]
+    fn should_error_instead_of_underflowing_when_too_many_recent_blocks_given() {
+        let mempool_txs = vec![];
+        let recent_blocks = vec![vec![], vec![]];
+        let hash_map = DepositInfoHashMap::new();
+        let result = scan_mempool_and_recent_blocks_for_deposits(
+            &mempool_txs,
+            &recent_blocks,
+            0,
+            &BtcNetwork::Testnet,
+            &hash_map,
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+
+use bitcoin::blockdata::transaction::OutPoint as BtcOutPoint;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    types::Result,
+    traits::DatabaseInterface,
+    btc::btc_types::{BtcUtxoAndValue, BtcUtxosAndValues},
+};
+
+// NOTE: rust-bitcoin removed its in-memory `UtxoSet` since it had no backing
+// store of its own - this is the store-backed replacement, keyed on the
+// outpoint each UTXO was created at so spends and reorgs can be tracked.
+const BTC_UTXO_SET_KEY_PREFIX: &str = "btc-utxo-set-";
+
+// NOTE: `DatabaseInterface` has no prefix-scan primitive, so membership of
+// the set is tracked explicitly via this index rather than relying on one.
+const BTC_UTXO_SET_INDEX_KEY: &str = "btc-utxo-set-index";
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BtcUtxoSetEntry {
+    pub value: u64,
+    pub utxo_and_value: BtcUtxoAndValue,
+    pub block_height_added: u64,
+    pub maybe_block_height_spent: Option<u64>,
+}
+
+impl BtcUtxoSetEntry {
+    fn new(utxo_and_value: BtcUtxoAndValue, block_height_added: u64) -> Self {
+        BtcUtxoSetEntry {
+            value: utxo_and_value.value,
+            utxo_and_value,
+            block_height_added,
+            maybe_block_height_spent: None,
+        }
+    }
+
+    fn is_spendable(&self) -> bool {
+        self.maybe_block_height_spent.is_none()
+    }
+}
+
+fn get_outpoint_key_string(outpoint: &BtcOutPoint) -> String {
+    format!("{}:{}", outpoint.txid, outpoint.vout)
+}
+
+fn get_db_key_from_outpoint(outpoint: &BtcOutPoint) -> Vec<u8> {
+    format!("{}{}", BTC_UTXO_SET_KEY_PREFIX, get_outpoint_key_string(outpoint)).into_bytes()
+}
+
+fn get_index_from_db<D: DatabaseInterface>(db: &D) -> Vec<String> {
+    db.get(BTC_UTXO_SET_INDEX_KEY.as_bytes().to_vec())
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+fn put_index_in_db<D: DatabaseInterface>(db: &D, index: &[String]) -> Result<()> {
+    db.put(BTC_UTXO_SET_INDEX_KEY.as_bytes().to_vec(), serde_json::to_vec(index)?)
+}
+
+fn add_to_index_if_absent<D: DatabaseInterface>(db: &D, outpoint: &BtcOutPoint) -> Result<()> {
+    let mut index = get_index_from_db(db);
+    let key = get_outpoint_key_string(outpoint);
+    if !index.contains(&key) {
+        index.push(key);
+        put_index_in_db(db, &index)?;
+    }
+    Ok(())
+}
+
+fn remove_from_index<D: DatabaseInterface>(db: &D, outpoint: &BtcOutPoint) -> Result<()> {
+    let key = get_outpoint_key_string(outpoint);
+    let index: Vec<String> = get_index_from_db(db)
+        .into_iter()
+        .filter(|existing_key| existing_key != &key)
+        .collect();
+    put_index_in_db(db, &index)
+}
+
+fn put_utxo_set_entry_in_db<D: DatabaseInterface>(
+    db: &D,
+    outpoint: &BtcOutPoint,
+    entry: &BtcUtxoSetEntry,
+) -> Result<()> {
+    db.put(get_db_key_from_outpoint(outpoint), serde_json::to_vec(entry)?)?;
+    add_to_index_if_absent(db, outpoint)
+}
+
+fn maybe_get_utxo_set_entry_from_db<D: DatabaseInterface>(
+    db: &D,
+    outpoint: &BtcOutPoint,
+) -> Option<BtcUtxoSetEntry> {
+    db.get(get_db_key_from_outpoint(outpoint))
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+}
+
+fn delete_utxo_set_entry_from_db<D: DatabaseInterface>(
+    db: &D,
+    outpoint: &BtcOutPoint,
+) -> Result<()> {
+    db.delete(get_db_key_from_outpoint(outpoint))?;
+    remove_from_index(db, outpoint)
+}
+
+/// A persistent, spend-aware record of the UTXOs this system controls,
+/// backed by the `DatabaseInterface` rather than held in RAM.
+pub struct BtcUtxoSet<'a, D: DatabaseInterface> {
+    db: &'a D,
+}
+
+impl<'a, D: DatabaseInterface> BtcUtxoSet<'a, D> {
+    pub fn new(db: &'a D) -> Self {
+        BtcUtxoSet { db }
+    }
+
+    /// Adds newly-seen UTXOs to the set, recording the block height at
+    /// which each one entered. Idempotent: an outpoint already tracked by
+    /// the set is left untouched rather than overwritten, so re-adding a
+    /// UTXO that's since been marked spent can't silently un-spend it.
+    pub fn add_utxos(
+        &self,
+        utxos: &BtcUtxosAndValues,
+        block_height: u64,
+    ) -> Result<()> {
+        info!("✔ Adding {} UTXO(s) to UTXO set at height {}...", utxos.len(), block_height);
+        utxos
+            .iter()
+            .try_for_each(|utxo_and_value| {
+                let outpoint = utxo_and_value.get_outpoint()?;
+                if maybe_get_utxo_set_entry_from_db(self.db, &outpoint).is_some() {
+                    debug!("✔ UTXO {} already in set ∴ not overwriting it", outpoint);
+                    return Ok(());
+                }
+                put_utxo_set_entry_in_db(
+                    self.db,
+                    &outpoint,
+                    &BtcUtxoSetEntry::new(utxo_and_value.clone(), block_height),
+                )
+            })
+    }
+
+    /// Returns whether `outpoint` is already tracked by the set, regardless
+    /// of whether it's still spendable.
+    pub fn contains(&self, outpoint: &BtcOutPoint) -> bool {
+        maybe_get_utxo_set_entry_from_db(self.db, outpoint).is_some()
+    }
+
+    /// Marks the UTXO at `outpoint` as spent at `block_height`, leaving it
+    /// in the set (rather than deleting it) so a later reorg can un-spend it.
+    pub fn mark_spent(&self, outpoint: &BtcOutPoint, block_height: u64) -> Result<()> {
+        match maybe_get_utxo_set_entry_from_db(self.db, outpoint) {
+            None => Err(format!("✘ Cannot mark unknown outpoint {} as spent!", outpoint).into()),
+            Some(mut entry) => {
+                info!("✔ Marking UTXO {} as spent at height {}...", outpoint, block_height);
+                entry.maybe_block_height_spent = Some(block_height);
+                put_utxo_set_entry_in_db(self.db, outpoint, &entry)
+            }
+        }
+    }
+
+    /// Returns the total value of all currently-unspent UTXOs in the set.
+    pub fn get_spendable_balance(&self) -> Result<u64> {
+        Ok(self.get_all_spendable_entries()?.iter().map(|entry| entry.value).sum())
+    }
+
+    /// Greedily selects the smallest set of spendable UTXOs whose combined
+    /// value is at least `target_value`, largest-first, to keep the
+    /// resulting input count low.
+    pub fn select_utxos_for_value(&self, target_value: u64) -> Result<BtcUtxosAndValues> {
+        info!("✔ Selecting UTXOs for target value of {} sats...", target_value);
+        let mut spendable_entries = self.get_all_spendable_entries()?;
+        spendable_entries.sort_by(|a, b| b.value.cmp(&a.value));
+        let mut accumulated_value = 0;
+        let mut selected = Vec::new();
+        for entry in spendable_entries {
+            if accumulated_value >= target_value {
+                break;
+            }
+            accumulated_value += entry.value;
+            selected.push(entry.utxo_and_value);
+        }
+        if accumulated_value < target_value {
+            return Err(format!(
+                "✘ Not enough spendable UTXOs to reach target value of {} sats!",
+                target_value,
+            ).into());
+        }
+        Ok(BtcUtxosAndValues::new(selected))
+    }
+
+    /// Rolls the UTXO set back to `canon_height` on a reorg: outputs that
+    /// entered the set at a greater height are deleted (they came from a
+    /// now-orphaned block), and outputs that were spent at a greater height
+    /// are un-spent (their spending tx has disappeared).
+    pub fn roll_back_to_height(&self, canon_height: u64) -> Result<()> {
+        info!("✔ Rolling UTXO set back to height {}...", canon_height);
+        self.get_all_entries()?
+            .into_iter()
+            .try_for_each(|(outpoint, mut entry)| {
+                if entry.block_height_added > canon_height {
+                    debug!("✔ Deleting orphaned UTXO {}...", outpoint);
+                    delete_utxo_set_entry_from_db(self.db, &outpoint)
+                } else if entry.maybe_block_height_spent.map(|h| h > canon_height).unwrap_or(false) {
+                    debug!("✔ Un-spending UTXO {} whose spending tx was orphaned...", outpoint);
+                    entry.maybe_block_height_spent = None;
+                    put_utxo_set_entry_in_db(self.db, &outpoint, &entry)
+                } else {
+                    Ok(())
+                }
+            })
+    }
+
+    /// Reads every entry currently tracked by the persisted outpoint index.
+    fn get_all_entries(&self) -> Result<HashMap<BtcOutPoint, BtcUtxoSetEntry>> {
+        get_index_from_db(self.db)
+            .iter()
+            .filter_map(|key_string| {
+                let db_key = format!("{}{}", BTC_UTXO_SET_KEY_PREFIX, key_string).into_bytes();
+                self.db.get(db_key).ok()
+            })
+            .map(|bytes| {
+                let entry: BtcUtxoSetEntry = serde_json::from_slice(&bytes)?;
+                let outpoint = entry.utxo_and_value.get_outpoint()?;
+                Ok((outpoint, entry))
+            })
+            .collect()
+    }
+
+    fn get_all_spendable_entries(&self) -> Result<Vec<BtcUtxoSetEntry>> {
+        Ok(self.get_all_entries()?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .filter(BtcUtxoSetEntry::is_spendable)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::{
+        script::Script as BtcScript,
+        transaction::{Transaction as BtcTransaction, TxOut as BtcTxOut},
+    };
+    use crate::{
+        test_utils::get_test_database,
+        btc::btc_utils::create_unsigned_utxo_from_tx,
+    };
+
+    fn get_sample_utxo_and_value(value: u64) -> BtcUtxoAndValue {
+        let tx = BtcTransaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![BtcTxOut { value, script_pubkey: BtcScript::new() }],
+        };
+        BtcUtxoAndValue::new(value, &create_unsigned_utxo_from_tx(&tx, 0), None, None)
+    }
+
+    #[test]
+    fn should_add_utxo_and_get_spendable_balance() {
+        let db = get_test_database();
+        let utxo_set = BtcUtxoSet::new(&db);
+        let utxos = BtcUtxosAndValues::new(vec![get_sample_utxo_and_value(1337)]);
+        utxo_set.add_utxos(&utxos, 100).unwrap();
+        assert_eq!(utxo_set.get_spendable_balance().unwrap(), 1337);
+    }
+
+    #[test]
+    fn should_select_utxos_for_value_and_exclude_spent_ones() {
+        let db = get_test_database();
+        let utxo_set = BtcUtxoSet::new(&db);
+        let utxo_1 = get_sample_utxo_and_value(1000);
+        let utxo_2 = get_sample_utxo_and_value(2000);
+        let outpoint_1 = utxo_1.get_outpoint().unwrap();
+        utxo_set.add_utxos(&BtcUtxosAndValues::new(vec![utxo_1, utxo_2]), 100).unwrap();
+        utxo_set.mark_spent(&outpoint_1, 101).unwrap();
+        assert_eq!(utxo_set.get_spendable_balance().unwrap(), 2000);
+        let selected = utxo_set.select_utxos_for_value(1500).unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].value, 2000);
+    }
+
+    #[test]
+    fn should_not_clobber_spent_state_when_re_adding_known_utxo() {
+        let db = get_test_database();
+        let utxo_set = BtcUtxoSet::new(&db);
+        let utxo = get_sample_utxo_and_value(1000);
+        let outpoint = utxo.get_outpoint().unwrap();
+        utxo_set.add_utxos(&BtcUtxosAndValues::new(vec![utxo.clone()]), 100).unwrap();
+        utxo_set.mark_spent(&outpoint, 101).unwrap();
+        utxo_set.add_utxos(&BtcUtxosAndValues::new(vec![utxo]), 200).unwrap();
+        assert_eq!(utxo_set.get_spendable_balance().unwrap(), 0);
+    }
+
+    #[test]
+    fn should_error_when_marking_unknown_outpoint_as_spent() {
+        let db = get_test_database();
+        let utxo_set = BtcUtxoSet::new(&db);
+        let outpoint = BtcOutPoint { txid: Default::default(), vout: 0 };
+        let result = utxo_set.mark_spent(&outpoint, 100);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn should_roll_back_to_height_and_delete_orphaned_utxo() {
+        let db = get_test_database();
+        let utxo_set = BtcUtxoSet::new(&db);
+        let utxo = get_sample_utxo_and_value(500);
+        utxo_set.add_utxos(&BtcUtxosAndValues::new(vec![utxo]), 200).unwrap();
+        utxo_set.roll_back_to_height(100).unwrap();
+        assert_eq!(utxo_set.get_spendable_balance().unwrap(), 0);
+    }
+}
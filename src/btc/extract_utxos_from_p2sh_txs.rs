@@ -3,13 +3,13 @@ use crate::{
     traits::DatabaseInterface,
     btc::{
         btc_state::BtcState,
+        btc_chain_data_source::{BtcChainDataSource, BtcBlockScanningDataSource},
         btc_database_utils::get_btc_network_from_db,
         btc_utils::{
             convert_deposit_info_to_json,
             create_unsigned_utxo_from_tx,
         },
         btc_types::{
-            BtcTransactions,
             BtcUtxoAndValue,
             BtcUtxosAndValues,
             DepositInfoHashMap,
@@ -20,6 +20,7 @@ use bitcoin::{
     util::address::Address as BtcAddress,
     network::constants::Network as BtcNetwork,
     blockdata::{
+        script::Script as BtcScript,
         transaction::{
             TxOut as BtcTxOut,
             Transaction as BtcTransaction,
@@ -92,50 +93,91 @@ fn maybe_extract_p2sh_utxo(
     }
 }
 
-pub fn extract_p2sh_utxos_from_txs(
-    transactions: &BtcTransactions,
+fn extract_p2sh_utxos_from_tx_slice(
+    transactions: &[BtcTransaction],
+    deposit_info_hash_map: &DepositInfoHashMap,
+    btc_network: &BtcNetwork,
+) -> BtcUtxosAndValues {
+    transactions
+        .iter()
+        .map(|full_tx|
+            full_tx
+                .output
+                .iter()
+                .enumerate()
+                .filter_map(|(i, tx_output)|
+                     maybe_extract_p2sh_utxo(
+                         i as u32,
+                         tx_output,
+                         full_tx,
+                         btc_network,
+                         deposit_info_hash_map
+                     )
+                )
+                .collect::<Vec<BtcUtxoAndValue>>()
+        )
+        .flatten()
+        .collect::<BtcUtxosAndValues>()
+}
+
+/// Extracts `p2sh` UTXOs via a `BtcChainDataSource`, so the core only ever
+/// has to ask for the transactions touching its own known deposit scripts
+/// rather than requiring a whole block's worth of transactions to be fed in.
+/// `BtcBlockScanningDataSource` (the default, block-scanning implementation)
+/// preserves today's behaviour for callers who still hand over whole blocks.
+pub fn extract_p2sh_utxos_from_txs<S: BtcChainDataSource>(
+    source: &S,
     deposit_info_hash_map: &DepositInfoHashMap,
     btc_network: &BtcNetwork,
 ) -> Result<BtcUtxosAndValues> {
-    info!("✔ Extracting UTXOs from `p2sh` transactions...");
-    Ok(
-        transactions
-            .iter()
-            .map(|full_tx|
-                full_tx
-                    .output
-                    .iter()
-                    .enumerate()
-                    .filter_map(|(i, tx_output)|
-                         maybe_extract_p2sh_utxo(
-                             i as u32,
-                             tx_output,
-                             full_tx,
-                             btc_network,
-                             deposit_info_hash_map
-                         )
-                    )
-                    .collect::<Vec<BtcUtxoAndValue>>()
-            )
-            .flatten()
-            .collect::<BtcUtxosAndValues>()
-    )
+    info!("✔ Extracting UTXOs from `p2sh` transactions via chain data source...");
+    let script_pubkeys: Vec<BtcScript> = deposit_info_hash_map
+        .keys()
+        .map(|btc_address| btc_address.script_pubkey())
+        .collect();
+    let txs = source
+        .get_script_history_batch(&script_pubkeys)?
+        .iter()
+        .map(|tx_ref| source.get_tx(tx_ref))
+        .collect::<Result<Vec<BtcTransaction>>>()?;
+    Ok(extract_p2sh_utxos_from_tx_slice(&txs, deposit_info_hash_map, btc_network))
 }
 
 pub fn maybe_extract_utxos_from_p2sh_txs_and_put_in_state<D>(
     state: BtcState<D>
 ) -> Result<BtcState<D>>
     where D: DatabaseInterface
+{
+    maybe_extract_utxos_from_p2sh_txs_and_put_in_state_with_confirmations(state, None)
+}
+
+/// As `maybe_extract_utxos_from_p2sh_txs_and_put_in_state`, but takes an
+/// optional confirmation count, which is logged alongside the number of
+/// extracted UTXOs. The count is not stored on the UTXOs or in the state:
+/// the UTXOs added to the state are the same as without it.
+pub fn maybe_extract_utxos_from_p2sh_txs_and_put_in_state_with_confirmations<D>(
+    state: BtcState<D>,
+    maybe_confirmations: Option<u64>,
+) -> Result<BtcState<D>>
+    where D: DatabaseInterface
 {
     info!("✔ Maybe extracting UTXOs from `p2sh` txs...");
+    let source = BtcBlockScanningDataSource::new(state.get_p2sh_deposit_txs()?.clone(), 0);
     extract_p2sh_utxos_from_txs(
-        state.get_p2sh_deposit_txs()?,
+        &source,
         state.get_deposit_info_hash_map()?,
         &get_btc_network_from_db(&state.db)?,
     )
         .and_then(|utxos| {
             debug!("✔ Extracted `p2sh` UTXOs: {:?}", utxos);
-            info!("✔ Extracted {} `p2sh` UTXOs", utxos.len());
+            match maybe_confirmations {
+                None => info!("✔ Extracted {} `p2sh` UTXOs", utxos.len()),
+                Some(confirmations) => info!(
+                    "✔ Extracted {} `p2sh` UTXOs at {} confirmation(s)",
+                    utxos.len(),
+                    confirmations,
+                ),
+            };
             state.add_utxos_and_values(utxos)
         })
 }
@@ -145,6 +187,7 @@ mod tests {
     use super::*;
     use std::str::FromStr;
     use crate::btc::{
+        btc_chain_data_source::BtcBlockScanningDataSource,
         filter_p2sh_deposit_txs::filter_p2sh_deposit_txs,
         get_deposit_info_hash_map::create_hash_map_from_deposit_info_list,
         btc_test_utils::{
@@ -220,8 +263,9 @@ mod tests {
             &txs,
             &btc_network,
         ).unwrap();
+        let source = BtcBlockScanningDataSource::new(filtered_txs, 0);
         let result = extract_p2sh_utxos_from_txs(
-            &filtered_txs,
+            &source,
             &hash_map,
             &btc_network,
         ).unwrap();
@@ -276,8 +320,9 @@ mod tests {
             &txs,
             &btc_network,
         ).unwrap();
+        let source = BtcBlockScanningDataSource::new(filtered_txs, 0);
         let result = extract_p2sh_utxos_from_txs(
-            &filtered_txs,
+            &source,
             &hash_map,
             &btc_network,
         ).unwrap();
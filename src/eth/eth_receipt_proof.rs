@@ -0,0 +1,239 @@
+use rlp::{Rlp, RlpStream};
+use ethereum_types::H256;
+use tiny_keccak::keccak256;
+
+use crate::{
+    types::{Bytes, Result},
+    traits::DatabaseInterface,
+    eth::{
+        eth_state::EthState,
+        eth_types::{EthBlock, EthReceipt},
+    },
+};
+
+/// A single step of a Merkle-Patricia proof: the raw, RLP-encoded trie node
+/// as returned by `eth_getProof`-style calls.
+pub type EthMerkleProofNode = Bytes;
+
+fn keccak(data: &[u8]) -> H256 {
+    H256::from(keccak256(data))
+}
+
+/// RLP-encodes the transaction's index the same way it's keyed into the
+/// receipts trie (i.e. as the trie path).
+fn get_trie_key_from_tx_index(tx_index: u64) -> Bytes {
+    let mut stream = RlpStream::new();
+    stream.append(&tx_index);
+    stream.out()
+}
+
+/// Expands a byte string into its big-endian nibbles, since the trie is
+/// keyed nibble-by-nibble rather than byte-by-byte.
+fn bytes_to_nibbles(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .iter()
+        .flat_map(|byte| vec![byte >> 4, byte & 0x0f])
+        .collect()
+}
+
+/// Decodes a hex-prefix (a.k.a. "compact") encoded path, as found in the
+/// first item of a leaf or extension node, into its raw nibbles plus
+/// whether the node is a leaf (vs an extension).
+fn decode_hex_prefix(encoded: &[u8]) -> Result<(Vec<u8>, bool)> {
+    if encoded.is_empty() {
+        return Err("✘ Empty hex-prefix encoded path in proof node!".into());
+    }
+    let first_byte = encoded[0];
+    let is_leaf = first_byte & 0x20 != 0;
+    let is_odd = first_byte & 0x10 != 0;
+    let mut nibbles = if is_odd { vec![first_byte & 0x0f] } else { vec![] };
+    encoded[1..].iter().for_each(|byte| {
+        nibbles.push(byte >> 4);
+        nibbles.push(byte & 0x0f);
+    });
+    Ok((nibbles, is_leaf))
+}
+
+/// Resolves a branch/extension node's child reference to the next node's raw
+/// RLP bytes. A child <32 bytes is embedded inline in the parent's RLP and
+/// needs no further hash check; a child >=32 bytes is referenced by its
+/// keccak hash, which must match the next node handed to us in `proof`.
+fn resolve_next_node<'a>(
+    child_rlp: &Rlp,
+    proof_iter: &mut std::slice::Iter<'a, EthMerkleProofNode>,
+) -> Result<Bytes> {
+    if child_rlp.is_list() {
+        return Ok(child_rlp.as_raw().to_vec());
+    }
+    let expected_child_hash_bytes = child_rlp.data()?;
+    if expected_child_hash_bytes.len() != 32 {
+        return Err("✘ Proof references a child but trie path ends in an empty slot!".into());
+    }
+    let expected_child_hash = H256::from_slice(expected_child_hash_bytes);
+    let next_node = proof_iter
+        .next()
+        .ok_or("✘ Proof ended before reaching a leaf value!")?
+        .clone();
+    if keccak(&next_node) != expected_child_hash {
+        return Err("✘ Next proof node does not hash to the expected child hash!".into());
+    }
+    Ok(next_node)
+}
+
+/// Walks an MPT inclusion proof for `trie_key_nibbles`, checking at each step
+/// that the current node hashes to what the parent node (or, for the root,
+/// the claimed root hash) pointed at, and that the hex-prefix-encoded path
+/// on leaf/extension nodes actually matches the remaining key. Returns the
+/// RLP value found at the terminal leaf/branch slot.
+fn walk_merkle_patricia_proof(
+    expected_root: &H256,
+    trie_key_nibbles: &[u8],
+    proof: &[EthMerkleProofNode],
+) -> Result<Bytes> {
+    let mut proof_iter = proof.iter();
+    let mut current_node_bytes = proof_iter
+        .next()
+        .ok_or("✘ Empty proof!")?
+        .clone();
+    if keccak(&current_node_bytes) != *expected_root {
+        return Err("✘ Root proof node does not hash to the expected receipts root!".into());
+    }
+    let mut remaining_nibbles = trie_key_nibbles.to_vec();
+    loop {
+        let node_rlp = Rlp::new(&current_node_bytes);
+        match node_rlp.item_count()? {
+            // Leaf/extension node: [hex-prefix encoded path, value or next node reference]
+            2 => {
+                let (path_nibbles, is_leaf) = decode_hex_prefix(node_rlp.at(0)?.data()?)?;
+                if !remaining_nibbles.starts_with(&path_nibbles) {
+                    return Err("✘ Proof path does not match the trie key!".into());
+                }
+                remaining_nibbles = remaining_nibbles[path_nibbles.len()..].to_vec();
+                let value_rlp = node_rlp.at(1)?;
+                if is_leaf {
+                    if !remaining_nibbles.is_empty() {
+                        return Err("✘ Leaf node reached with key nibbles still remaining!".into());
+                    }
+                    return Ok(value_rlp.data()?.to_vec());
+                }
+                current_node_bytes = resolve_next_node(&value_rlp, &mut proof_iter)?;
+            }
+            // Branch node: 16 nibble-indexed children + a value slot
+            17 => {
+                if remaining_nibbles.is_empty() {
+                    return Ok(node_rlp.at(16)?.data()?.to_vec());
+                }
+                let nibble = remaining_nibbles[0] as usize;
+                remaining_nibbles = remaining_nibbles[1..].to_vec();
+                current_node_bytes = resolve_next_node(&node_rlp.at(nibble)?, &mut proof_iter)?;
+            }
+            n => return Err(format!("✘ Unexpected node with {} items in proof!", n).into()),
+        };
+    }
+}
+
+/// Verifies that `receipt` is included at `tx_index` in the trie committed
+/// to by `block`'s `receipts_root`, via the supplied MPT inclusion `proof`.
+/// This lets a caller submit a single proven receipt instead of the crate
+/// having to trust (or be handed) the full receipts array for a block.
+pub fn verify_receipt_inclusion(
+    block: &EthBlock,
+    tx_index: u64,
+    receipt: &EthReceipt,
+    proof: &[EthMerkleProofNode],
+) -> Result<bool> {
+    info!("✔ Verifying inclusion proof for tx index {}...", tx_index);
+    let trie_key_nibbles = bytes_to_nibbles(&get_trie_key_from_tx_index(tx_index));
+    let leaf_value = walk_merkle_patricia_proof(&block.receipts_root, &trie_key_nibbles, proof)?;
+    let expected_receipt_rlp = rlp::encode(receipt);
+    let is_valid = leaf_value == expected_receipt_rlp;
+    if is_valid {
+        info!("✔ Receipt inclusion proof is valid!");
+    } else {
+        info!("✘ Receipt inclusion proof is INVALID - decoded leaf does not match receipt!");
+    }
+    Ok(is_valid)
+}
+
+/// Verifies `receipt`'s inclusion proof against `state`'s submitted block
+/// header, so canonization can operate on an individually-proven receipt
+/// rather than requiring the full receipts array for that block.
+pub fn maybe_verify_receipt_inclusion_and_put_in_state<D>(
+    state: EthState<D>,
+    tx_index: u64,
+    receipt: EthReceipt,
+    proof: Vec<EthMerkleProofNode>,
+) -> Result<EthState<D>>
+    where D: DatabaseInterface
+{
+    let block = state.get_eth_block()?;
+    match verify_receipt_inclusion(block, tx_index, &receipt, &proof)? {
+        false => Err("✘ Submitted receipt failed its Merkle-Patricia inclusion proof!".into()),
+        true => state.add_eth_receipt(receipt),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_hex_prefix(nibbles: &[u8], is_leaf: bool) -> Bytes {
+        let is_odd = nibbles.len() % 2 == 1;
+        let mut flag = if is_leaf { 0x20 } else { 0x00 };
+        if is_odd {
+            flag |= 0x10;
+        }
+        let mut encoded = Vec::new();
+        let mut remaining = nibbles;
+        if is_odd {
+            encoded.push(flag | remaining[0]);
+            remaining = &remaining[1..];
+        } else {
+            encoded.push(flag);
+        }
+        remaining
+            .chunks(2)
+            .for_each(|pair| encoded.push((pair[0] << 4) | pair[1]));
+        encoded
+    }
+
+    fn build_leaf_node(path_nibbles: &[u8], value: &[u8]) -> Bytes {
+        let mut stream = RlpStream::new_list(2);
+        stream.append(&encode_hex_prefix(path_nibbles, true));
+        stream.append(&value.to_vec());
+        stream.out()
+    }
+
+    #[test]
+    fn should_get_trie_key_from_tx_index() {
+        let result = get_trie_key_from_tx_index(0);
+        assert_eq!(result, rlp::encode(&0u64));
+    }
+
+    #[test]
+    fn should_error_when_proof_node_does_not_match_expected_hash() {
+        let root = H256::zero();
+        let proof = vec![vec![1, 2, 3]];
+        let result = walk_merkle_patricia_proof(&root, &[0], &proof);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn should_walk_happy_path_single_leaf_proof() {
+        let trie_key_nibbles = bytes_to_nibbles(&get_trie_key_from_tx_index(0));
+        let value = vec![0xde, 0xad, 0xbe, 0xef];
+        let leaf_node = build_leaf_node(&trie_key_nibbles, &value);
+        let root = keccak(&leaf_node);
+        let result = walk_merkle_patricia_proof(&root, &trie_key_nibbles, &[leaf_node]).unwrap();
+        assert_eq!(result, value);
+    }
+
+    #[test]
+    fn should_decode_hex_prefix_round_trip() {
+        let nibbles = vec![0x1, 0x2, 0x3];
+        let encoded = encode_hex_prefix(&nibbles, true);
+        let (decoded_nibbles, is_leaf) = decode_hex_prefix(&encoded).unwrap();
+        assert_eq!(decoded_nibbles, nibbles);
+        assert!(is_leaf);
+    }
+}